@@ -0,0 +1,143 @@
+//! A memory-mapped bus that routes loads and stores to attached devices.
+
+use std::ops::Range;
+
+/// A memory-mapped peripheral that can be attached to a [`Bus`].
+///
+/// Addresses passed to [`Device::read`]/[`Device::write`] are relative to
+/// the start of the range the device was [`Bus::map`]ped into.
+pub trait Device {
+    /// Read `1 << size` bytes at `offset`. The bus guarantees `size <= 2`
+    /// (at most 4 bytes, since accesses are carried in a `u32`) and that
+    /// `offset + (1 << size)` falls within the range this device was
+    /// mapped into, so implementations do not need to re-check either.
+    fn read(&self, offset: u32, size: u8) -> u32;
+    /// Write `1 << size` bytes of `value` at `offset`. See [`Device::read`]
+    /// for the guarantees the bus makes about `offset` and `size`.
+    fn write(&self, offset: u32, value: u32, size: u8);
+}
+
+/// Dispatches loads and stores to the device mapped at the target address.
+#[derive(Default)]
+pub struct Bus<'a> {
+    devices: Vec<(Range<u32>, Box<dyn Device + 'a>)>,
+}
+
+impl<'a> Bus<'a> {
+    /// Create a bus with no devices mapped.
+    pub fn new() -> Self {
+        Self {
+            devices: Vec::new(),
+        }
+    }
+
+    /// Map `device` into `range`. Later mappings take priority over earlier
+    /// ones that overlap.
+    pub fn map(&mut self, range: Range<u32>, device: Box<dyn Device + 'a>) {
+        self.devices.push((range, device));
+    }
+
+    /// Find the device mapped at `addr`, rejecting accesses wider than a
+    /// `u32` (`size > 2`) and accesses whose `1 << size`-byte window would
+    /// run past the end of the device's mapped range.
+    fn find(&self, addr: u32, size: u8) -> Option<(u32, &(dyn Device + 'a))> {
+        if size > 2 {
+            return None;
+        }
+        let end = addr.checked_add(1u32 << size)?;
+        self.devices
+            .iter()
+            .rev()
+            .find(|(range, _)| range.contains(&addr) && end <= range.end)
+            .map(|(range, device)| (addr - range.start, device.as_ref()))
+    }
+
+    /// Read from the device mapped at `addr`, or `None` if nothing is
+    /// mapped there (or the access is out of range or too wide).
+    pub fn read(&self, addr: u32, size: u8) -> Option<u32> {
+        self.find(addr, size)
+            .map(|(offset, device)| device.read(offset, size))
+    }
+
+    /// Write to the device mapped at `addr`; returns whether a device was
+    /// mapped there (and the access was in range and narrow enough).
+    pub fn write(&self, addr: u32, value: u32, size: u8) -> bool {
+        match self.find(addr, size) {
+            Some((offset, device)) => {
+                device.write(offset, value, size);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A device that echoes back a fixed value on reads and ignores writes.
+    struct RecordingDevice;
+
+    impl Device for RecordingDevice {
+        fn read(&self, _offset: u32, _size: u8) -> u32 {
+            0x1234
+        }
+
+        fn write(&self, _offset: u32, _value: u32, _size: u8) {}
+    }
+
+    /// A device that returns its own fixed value on reads and ignores writes.
+    struct FixedDevice(u32);
+
+    impl Device for FixedDevice {
+        fn read(&self, _offset: u32, _size: u8) -> u32 {
+            self.0
+        }
+
+        fn write(&self, _offset: u32, _value: u32, _size: u8) {}
+    }
+
+    #[test]
+    fn read_write_dispatch_to_the_mapped_device() {
+        let mut bus = Bus::new();
+        bus.map(0x1000..0x1010, Box::new(RecordingDevice));
+        assert_eq!(bus.read(0x1004, 2), Some(0x1234));
+        assert_eq!(bus.write(0x1004, 0xaa, 2), true);
+    }
+
+    #[test]
+    fn unmapped_address_is_none() {
+        let bus: Bus = Bus::new();
+        assert_eq!(bus.read(0x1000, 2), None);
+        assert_eq!(bus.write(0x1000, 0, 2), false);
+    }
+
+    #[test]
+    fn later_mapping_shadows_earlier_overlap() {
+        let mut bus = Bus::new();
+        bus.map(0x1000..0x2000, Box::new(RecordingDevice));
+        bus.map(0x1800..0x1810, Box::new(FixedDevice(0x5678)));
+        // Both devices cover 0x1800, the more recently mapped one wins.
+        assert_eq!(bus.read(0x1800, 2), Some(0x5678));
+    }
+
+    #[test]
+    fn size_wider_than_a_word_is_rejected() {
+        let mut bus = Bus::new();
+        bus.map(0x1000..0x1010, Box::new(RecordingDevice));
+        assert_eq!(bus.read(0x1000, 3), None);
+        assert_eq!(bus.write(0x1000, 0, 3), false);
+    }
+
+    #[test]
+    fn access_straddling_the_end_of_the_range_is_rejected() {
+        let mut bus = Bus::new();
+        bus.map(0x1000..0x1004, Box::new(RecordingDevice));
+        // The range is 4 bytes wide; a 4-byte access at the last in-range
+        // byte would run three bytes past the end.
+        assert_eq!(bus.read(0x1003, 2), None);
+        // But a narrower access at the same address is legal.
+        assert_eq!(bus.read(0x1003, 0), Some(0x1234));
+    }
+}
@@ -1,11 +1,178 @@
 //! Engine for dynamic binary translation and execution
 
+use crate::bus::{Bus, Device};
 use crate::tran::ElfTranslator;
 use anyhow::{bail, Result};
 use llvm_sys::{
     core::*, execution_engine::*, prelude::*, support::*, transforms::pass_manager_builder::*,
 };
 use std::cell::Cell;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// The address at which the TCDM window starts.
+const TCDM_START: u32 = 0x42000;
+/// The address at which the TCDM window ends.
+const TCDM_END: u32 = 0x43000;
+/// The address at which the cluster configuration registers start
+/// (`tcdm_start`, `tcdm_end`, `nr_cores`).
+const CFG_START: u32 = 0x40000000;
+/// The address of the scratch register used to report the exit code.
+const SCRATCH_REG: u32 = 0x40000020;
+/// The address at which the timer's `mtime`/`mtimecmp` registers start.
+const TIMER_START: u32 = 0x40000030;
+
+/// Exception cause: load address misaligned.
+const CAUSE_LOAD_ADDR_MISALIGNED: u32 = 4;
+/// Exception cause: load access fault.
+const CAUSE_LOAD_ACCESS_FAULT: u32 = 5;
+/// Exception cause: store/AMO address misaligned.
+const CAUSE_STORE_ADDR_MISALIGNED: u32 = 6;
+/// Exception cause: store/AMO access fault.
+const CAUSE_STORE_ACCESS_FAULT: u32 = 7;
+/// Exception cause: environment call from M-mode.
+const CAUSE_ECALL: u32 = 11;
+/// Exception cause: breakpoint.
+const CAUSE_BREAKPOINT: u32 = 3;
+/// Exception cause: illegal instruction.
+const CAUSE_ILLEGAL_INSTRUCTION: u32 = 2;
+/// Exception cause: machine timer interrupt.
+const CAUSE_MACHINE_TIMER_INTERRUPT: u32 = 0x80000007;
+
+/// FCSR accrued exception flag: invalid operation.
+const FFLAG_NV: u32 = 1 << 4;
+/// FCSR accrued exception flag: divide by zero.
+const FFLAG_DZ: u32 = 1 << 3;
+/// FCSR accrued exception flag: overflow.
+const FFLAG_OF: u32 = 1 << 2;
+/// FCSR accrued exception flag: underflow.
+const FFLAG_UF: u32 = 1 << 1;
+/// FCSR accrued exception flag: inexact.
+const FFLAG_NX: u32 = 1 << 0;
+
+/// Round `a` the way `fcvt.*` would for the (already-resolved) rounding
+/// mode `rm`.
+fn round_for_mode_f32(a: f32, rm: u8) -> f32 {
+    match rm {
+        1 => a.trunc(),           // RTZ
+        2 => a.floor(),           // RDN
+        3 => a.ceil(),            // RUP
+        4 => a.round(),           // RMM: ties away from zero
+        _ => a.round_ties_even(), // RNE (and any reserved encoding)
+    }
+}
+
+/// Double-precision counterpart of [`round_for_mode_f32`].
+fn round_for_mode_f64(a: f64, rm: u8) -> f64 {
+    match rm {
+        1 => a.trunc(),
+        2 => a.floor(),
+        3 => a.ceil(),
+        4 => a.round(),
+        _ => a.round_ties_even(),
+    }
+}
+
+/// The next representable `f32` strictly greater than `x`.
+fn next_up_f32(x: f32) -> f32 {
+    if x.is_nan() || x == f32::INFINITY {
+        return x;
+    }
+    let bits = x.to_bits();
+    f32::from_bits(if x == 0.0 {
+        1
+    } else if x > 0.0 {
+        bits + 1
+    } else {
+        bits - 1
+    })
+}
+
+/// The next representable `f32` strictly less than `x`.
+fn next_down_f32(x: f32) -> f32 {
+    -next_up_f32(-x)
+}
+
+/// The next representable `f64` strictly greater than `x`.
+fn next_up_f64(x: f64) -> f64 {
+    if x.is_nan() || x == f64::INFINITY {
+        return x;
+    }
+    let bits = x.to_bits();
+    f64::from_bits(if x == 0.0 {
+        1
+    } else if x > 0.0 {
+        bits + 1
+    } else {
+        bits - 1
+    })
+}
+
+/// The next representable `f64` strictly less than `x`.
+fn next_down_f64(x: f64) -> f64 {
+    -next_up_f64(-x)
+}
+
+/// Nudge a correctly-rounded-to-nearest-even `nearest` by at most one ULP
+/// to honor a directed rounding mode, given `error` = `exact - nearest`
+/// (or a value with the same sign, e.g. a residual computed via fma).
+/// RNE and RMM trust the native nearest-even rounding as-is.
+fn round_directed_f32(nearest: f32, error: f32, rm: u8) -> f32 {
+    if error == 0.0 || !nearest.is_finite() {
+        return nearest;
+    }
+    match rm {
+        1 /* RTZ */ => {
+            if nearest >= 0.0 {
+                if error < 0.0 { next_down_f32(nearest) } else { nearest }
+            } else if error > 0.0 {
+                next_up_f32(nearest)
+            } else {
+                nearest
+            }
+        }
+        2 /* RDN */ => if error < 0.0 { next_down_f32(nearest) } else { nearest },
+        3 /* RUP */ => if error > 0.0 { next_up_f32(nearest) } else { nearest },
+        _ => nearest,
+    }
+}
+
+/// Double-precision counterpart of [`round_directed_f32`].
+fn round_directed_f64(nearest: f64, error: f64, rm: u8) -> f64 {
+    if error == 0.0 || !nearest.is_finite() {
+        return nearest;
+    }
+    match rm {
+        1 /* RTZ */ => {
+            if nearest >= 0.0 {
+                if error < 0.0 { next_down_f64(nearest) } else { nearest }
+            } else if error > 0.0 {
+                next_up_f64(nearest)
+            } else {
+                nearest
+            }
+        }
+        2 /* RDN */ => if error < 0.0 { next_down_f64(nearest) } else { nearest },
+        3 /* RUP */ => if error > 0.0 { next_up_f64(nearest) } else { nearest },
+        _ => nearest,
+    }
+}
+
+/// Compute the exact value of `a * b + c - result` for a correctly-rounded
+/// `result = a.mul_add(b, c)`, via a TwoProduct (Veltkamp/Dekker) +
+/// TwoSum (Knuth) error-free transform. Unlike the `fdiv`/`fsqrt` residual
+/// trick below (which gets its exactness for free from cancellation),
+/// `a * b + c` has no such guarantee, so this needs the full two-step
+/// decomposition; it is exact barring overflow/underflow, which callers
+/// already check for separately.
+fn fma_residual_f64(a: f64, b: f64, c: f64, result: f64) -> f64 {
+    let u1 = a * b;
+    let u2 = a.mul_add(b, -u1);
+    let s1 = u1 + c;
+    let v = s1 - u1;
+    let s2 = (u1 - (s1 - v)) + (c - v);
+    (s1 - result) + (s2 + u2)
+}
 
 /// An execution engine.
 pub struct Engine {
@@ -14,13 +181,32 @@ pub struct Engine {
     /// The LLVM module which contains the translated code.
     pub module: LLVMModuleRef,
     /// The exit code set by the binary.
-    pub exit_code: Cell<u32>,
+    pub exit_code: AtomicU32,
     /// Optimize the LLVM IR.
     pub opt_llvm: bool,
     /// Optimize during JIT compilation.
     pub opt_jit: bool,
+    /// The number of cores (harts) in the cluster.
+    pub nr_cores: usize,
+    /// The number of retired instructions between two ticks of `mtime`.
+    pub tick_rate: u64,
+    /// If set, write a RISC-V disassembly listing of the translated binary
+    /// to this path. Only honored when built with the `disasm` feature.
+    pub dump_riscv: Option<std::path::PathBuf>,
+    /// If set, write the post-optimization LLVM IR to this path. Only
+    /// honored when built with the `disasm` feature.
+    pub dump_llvm_ir: Option<std::path::PathBuf>,
 }
 
+// SAFETY: `context` and `module` are raw LLVM handles, which are never
+// auto-`Sync`. `execute_inner` shares `&Engine` with every hart thread it
+// spawns, but those threads only ever call the already-JIT-compiled `exec`
+// function and read the plain `nr_cores`/`tick_rate` fields; none of them
+// mutate the LLVM context or module after JIT compilation has finished, so
+// concurrent read-only access through a shared reference is sound. The one
+// field hart threads do mutate, `exit_code`, is an atomic for this reason.
+unsafe impl Sync for Engine {}
+
 impl Engine {
     /// Create a new execution engine.
     pub fn new(context: LLVMContextRef) -> Self {
@@ -39,6 +225,10 @@ impl Engine {
             exit_code: Default::default(),
             opt_llvm: true,
             opt_jit: true,
+            nr_cores: 1,
+            tick_rate: 1,
+            dump_riscv: None,
+            dump_llvm_ir: None,
         }
     }
 
@@ -48,6 +238,12 @@ impl Engine {
 
         // Dump the contents of the binary.
         debug!("Loading ELF binary");
+        #[cfg(feature = "disasm")]
+        let mut riscv_dump = self
+            .dump_riscv
+            .as_ref()
+            .map(|path| -> Result<_> { Ok(std::io::BufWriter::new(std::fs::File::create(path)?)) })
+            .transpose()?;
         for section in tran.sections() {
             debug!(
                 "Loading ELF section `{}` from 0x{:x} to 0x{:x}",
@@ -57,6 +253,11 @@ impl Engine {
             );
             for (addr, inst) in tran.instructions(section) {
                 trace!("  - 0x{:x}: {}", addr, inst);
+                #[cfg(feature = "disasm")]
+                if let Some(w) = riscv_dump.as_mut() {
+                    use std::io::Write;
+                    writeln!(w, "0x{:08x}: {}", addr, inst)?;
+                }
             }
         }
 
@@ -71,6 +272,22 @@ impl Engine {
             unsafe { self.optimize() };
         }
 
+        // Dump the post-optimization LLVM IR, if requested.
+        #[cfg(feature = "disasm")]
+        if let Some(path) = &self.dump_llvm_ir {
+            unsafe { self.dump_llvm_ir(path)? };
+        }
+
+        Ok(())
+    }
+
+    /// Write the current LLVM IR of [`Self::module`] to `path`.
+    #[cfg(feature = "disasm")]
+    unsafe fn dump_llvm_ir(&self, path: &std::path::Path) -> Result<()> {
+        let ir = LLVMPrintModuleToString(self.module);
+        let ir_str = std::ffi::CStr::from_ptr(ir).to_string_lossy().into_owned();
+        LLVMDisposeMessage(ir);
+        std::fs::write(path, ir_str)?;
         Ok(())
     }
 
@@ -119,34 +336,100 @@ impl Engine {
         }
 
         // Lookup the function which executes the binary.
-        let exec: extern "C" fn(&Cpu<'b>) = std::mem::transmute(LLVMGetFunctionAddress(
-            ee,
-            b"execute_binary\0".as_ptr() as *const _,
-        ));
-        debug!("Translated binary is at {:?}", exec as *const i8);
-
-        // Create a CPU.
-        let cpu = Cpu::new(self);
-        trace!("Initial state: {:#?}", cpu.state);
-
-        // Execute the binary.
-        debug!("Launching binary");
+        let addr = LLVMGetFunctionAddress(ee, b"execute_binary\0".as_ptr() as *const _);
+        debug!("Translated binary is at {:?}", addr as *const i8);
+        let exec: SendFn = SendFn(addr);
+
+        // Set up the cluster-wide shared state: the TCDM window, the hart
+        // barrier, and the `mtime` counter, all shared by every core's
+        // thread. `mtimecmp` is per-hart and constructed inside `Cpu::new`.
+        let tcdm = Arc::new(Mutex::new(vec![0u8; (TCDM_END - TCDM_START) as usize]));
+        let barrier = Arc::new(Barrier::new(self.nr_cores));
+        let mtime = Arc::new(AtomicU64::new(0));
+
+        // Launch one OS thread per hart, each with its own CPU state but
+        // sharing the TCDM memory, barrier, and mtime counter above.
+        debug!("Launching {} hart(s)", self.nr_cores);
         let t0 = std::time::Instant::now();
-        exec(&cpu);
+        let results: Vec<_> = std::thread::scope(|scope| {
+            (0..self.nr_cores)
+                .map(|hartid| {
+                    let tcdm = tcdm.clone();
+                    let barrier = barrier.clone();
+                    let mtime = mtime.clone();
+                    let exec = exec;
+                    scope.spawn(move || {
+                        let cpu = Cpu::new(self, hartid as u32, tcdm, barrier, mtime);
+                        trace!("Hart {} initial state: {:#?}", hartid, cpu.state);
+                        let exec: extern "C" fn(&Cpu<'b>) = std::mem::transmute(exec.0);
+                        exec(&cpu);
+                        trace!("Hart {} final state: {:#?}", hartid, cpu.state);
+                        cpu.state.instret
+                    })
+                })
+                .collect::<Vec<_>>()
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .collect()
+        });
         let t1 = std::time::Instant::now();
         let duration = (t1.duration_since(t0)).as_secs_f64();
 
-        trace!("Final state: {:#?}", cpu.state);
-        debug!("Exit code is 0x{:x}", self.exit_code.get());
+        debug!("Exit code is 0x{:x}", self.exit_code.load(Ordering::Relaxed));
+        let total_instret: u64 = results.iter().sum();
+        for (hartid, instret) in results.iter().enumerate() {
+            info!("Hart {} retired {} inst", hartid, instret);
+        }
         info!(
-            "Retired {} inst, {} inst/s",
-            cpu.state.instret,
-            cpu.state.instret as f64 / duration
+            "Retired {} inst total, {} inst/s",
+            total_instret,
+            total_instret as f64 / duration
         );
         Ok(())
     }
 }
 
+/// A wrapper that makes a JIT-compiled function pointer `Send` so it can be
+/// handed to the threads executing the individual harts.
+#[derive(Clone, Copy)]
+struct SendFn(u64);
+unsafe impl Send for SendFn {}
+
+/// A barrier that synchronizes all harts in the cluster, mirroring the
+/// core0/core1 startup + mailbox rendezvous used to bring up the cluster.
+pub struct Barrier {
+    nr_cores: usize,
+    state: Mutex<(usize, usize)>, // (number arrived, generation)
+    cond: Condvar,
+}
+
+impl Barrier {
+    /// Create a new barrier for `nr_cores` participants.
+    pub fn new(nr_cores: usize) -> Self {
+        Self {
+            nr_cores,
+            state: Mutex::new((0, 0)),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Block until all harts have reached the barrier.
+    pub fn wait(&self) {
+        let mut state = self.state.lock().unwrap();
+        let generation = state.1;
+        state.0 += 1;
+        if state.0 == self.nr_cores {
+            state.0 = 0;
+            state.1 = state.1.wrapping_add(1);
+            self.cond.notify_all();
+        } else {
+            while state.1 == generation {
+                state = self.cond.wait(state).unwrap();
+            }
+        }
+    }
+}
+
 pub unsafe fn add_llvm_symbols() {
     LLVMAddSymbol(
         b"banshee_load\0".as_ptr() as *const _,
@@ -164,6 +447,82 @@ pub unsafe fn add_llvm_symbols() {
         b"banshee_csr_write\0".as_ptr() as *const _,
         Cpu::binary_csr_write as *mut _,
     );
+    LLVMAddSymbol(
+        b"banshee_barrier\0".as_ptr() as *const _,
+        Cpu::binary_barrier as *mut _,
+    );
+    LLVMAddSymbol(
+        b"banshee_trap_taken\0".as_ptr() as *const _,
+        Cpu::binary_trap_taken as *mut _,
+    );
+    LLVMAddSymbol(
+        b"banshee_timer_tick\0".as_ptr() as *const _,
+        Cpu::binary_timer_tick as *mut _,
+    );
+    LLVMAddSymbol(
+        b"banshee_ecall\0".as_ptr() as *const _,
+        Cpu::binary_ecall as *mut _,
+    );
+    LLVMAddSymbol(
+        b"banshee_ebreak\0".as_ptr() as *const _,
+        Cpu::binary_ebreak as *mut _,
+    );
+    LLVMAddSymbol(
+        b"banshee_fma_s\0".as_ptr() as *const _,
+        Cpu::binary_fma_s as *mut _,
+    );
+    LLVMAddSymbol(
+        b"banshee_fma_d\0".as_ptr() as *const _,
+        Cpu::binary_fma_d as *mut _,
+    );
+    LLVMAddSymbol(
+        b"banshee_fdiv_s\0".as_ptr() as *const _,
+        Cpu::binary_fdiv_s as *mut _,
+    );
+    LLVMAddSymbol(
+        b"banshee_fdiv_d\0".as_ptr() as *const _,
+        Cpu::binary_fdiv_d as *mut _,
+    );
+    LLVMAddSymbol(
+        b"banshee_fsqrt_s\0".as_ptr() as *const _,
+        Cpu::binary_fsqrt_s as *mut _,
+    );
+    LLVMAddSymbol(
+        b"banshee_fsqrt_d\0".as_ptr() as *const _,
+        Cpu::binary_fsqrt_d as *mut _,
+    );
+    LLVMAddSymbol(
+        b"banshee_fcvt_w_s\0".as_ptr() as *const _,
+        Cpu::binary_fcvt_w_s as *mut _,
+    );
+    LLVMAddSymbol(
+        b"banshee_fcvt_wu_s\0".as_ptr() as *const _,
+        Cpu::binary_fcvt_wu_s as *mut _,
+    );
+    LLVMAddSymbol(
+        b"banshee_fcvt_s_w\0".as_ptr() as *const _,
+        Cpu::binary_fcvt_s_w as *mut _,
+    );
+    LLVMAddSymbol(
+        b"banshee_fcvt_s_wu\0".as_ptr() as *const _,
+        Cpu::binary_fcvt_s_wu as *mut _,
+    );
+    LLVMAddSymbol(
+        b"banshee_fcvt_w_d\0".as_ptr() as *const _,
+        Cpu::binary_fcvt_w_d as *mut _,
+    );
+    LLVMAddSymbol(
+        b"banshee_fcvt_wu_d\0".as_ptr() as *const _,
+        Cpu::binary_fcvt_wu_d as *mut _,
+    );
+    LLVMAddSymbol(
+        b"banshee_fcvt_d_w\0".as_ptr() as *const _,
+        Cpu::binary_fcvt_d_w as *mut _,
+    );
+    LLVMAddSymbol(
+        b"banshee_fcvt_d_wu\0".as_ptr() as *const _,
+        Cpu::binary_fcvt_d_wu as *mut _,
+    );
 }
 
 /// A CPU pointer to be passed to the binary code.
@@ -171,6 +530,107 @@ pub unsafe fn add_llvm_symbols() {
 pub struct Cpu<'a> {
     engine: &'a Engine,
     state: CpuState,
+    /// The memory/MMIO bus this core's loads and stores are dispatched
+    /// through.
+    bus: Bus<'a>,
+    /// The barrier used to synchronize all harts.
+    barrier: Arc<Barrier>,
+    /// The free-running timer tick count.
+    mtime: Arc<AtomicU64>,
+    /// The value of `mtime` at which a machine timer interrupt fires.
+    mtimecmp: Arc<AtomicU64>,
+}
+
+/// The TCDM scratchpad, backed by a byte buffer shared by every hart.
+struct TcdmDevice(Arc<Mutex<Vec<u8>>>);
+
+impl Device for TcdmDevice {
+    fn read(&self, offset: u32, size: u8) -> u32 {
+        let offset = offset as usize;
+        let len = 1usize << size;
+        let mem = self.0.lock().unwrap();
+        let mut bytes = [0u8; 4];
+        bytes[..len].copy_from_slice(&mem[offset..offset + len]);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn write(&self, offset: u32, value: u32, size: u8) {
+        let offset = offset as usize;
+        let len = 1usize << size;
+        let mut mem = self.0.lock().unwrap();
+        mem[offset..offset + len].copy_from_slice(&value.to_le_bytes()[..len]);
+    }
+}
+
+/// The read-only cluster configuration registers.
+struct ClusterInfoDevice {
+    tcdm_start: u32,
+    tcdm_end: u32,
+    nr_cores: u32,
+}
+
+impl Device for ClusterInfoDevice {
+    fn read(&self, offset: u32, _size: u8) -> u32 {
+        match offset {
+            0x00 => self.tcdm_start,
+            0x08 => self.tcdm_end,
+            0x10 => self.nr_cores,
+            _ => 0,
+        }
+    }
+
+    fn write(&self, _offset: u32, _value: u32, _size: u8) {}
+}
+
+/// The scratch register used by the binary to report its exit code.
+struct ScratchDevice<'a>(&'a AtomicU32);
+
+impl<'a> Device for ScratchDevice<'a> {
+    fn read(&self, _offset: u32, _size: u8) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn write(&self, _offset: u32, value: u32, _size: u8) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+}
+
+/// A programmable timer, exposing a free-running `mtime` counter and a
+/// per-hart `mtimecmp` compare register as a pair of 64-bit MMIO registers.
+struct TimerDevice {
+    mtime: Arc<AtomicU64>,
+    mtimecmp: Arc<AtomicU64>,
+}
+
+impl Device for TimerDevice {
+    fn read(&self, offset: u32, _size: u8) -> u32 {
+        match offset {
+            0x0 => self.mtime.load(Ordering::Relaxed) as u32,
+            0x4 => (self.mtime.load(Ordering::Relaxed) >> 32) as u32,
+            0x8 => self.mtimecmp.load(Ordering::Relaxed) as u32,
+            0xc => (self.mtimecmp.load(Ordering::Relaxed) >> 32) as u32,
+            _ => 0,
+        }
+    }
+
+    fn write(&self, offset: u32, value: u32, _size: u8) {
+        let set_half = |reg: &AtomicU64, hi: bool| {
+            let old = reg.load(Ordering::Relaxed);
+            let new = if hi {
+                (old & 0xffff_ffff) | ((value as u64) << 32)
+            } else {
+                (old & 0xffff_ffff_0000_0000) | value as u64
+            };
+            reg.store(new, Ordering::Relaxed);
+        };
+        match offset {
+            0x0 => set_half(&self.mtime, false),
+            0x4 => set_half(&self.mtime, true),
+            0x8 => set_half(&self.mtimecmp, false),
+            0xc => set_half(&self.mtimecmp, true),
+            _ => (),
+        }
+    }
 }
 
 /// A representation of a single CPU core's state.
@@ -180,45 +640,570 @@ pub struct CpuState {
     regs: [u32; 32],
     pc: u32,
     instret: u64,
+    /// The hart ID of this core, as returned by the `mhartid` CSR.
+    hartid: u32,
+    /// Machine trap-vector base address.
+    mtvec: Cell<u32>,
+    /// Machine exception program counter.
+    mepc: Cell<u32>,
+    /// Machine trap cause.
+    mcause: Cell<u32>,
+    /// Machine bad address or instruction.
+    mtval: Cell<u32>,
+    /// Machine status register.
+    mstatus: Cell<u32>,
+    /// Set once a trap has been raised and not yet taken by the translated
+    /// code.
+    trap_pending: Cell<bool>,
+    /// The FP register file (RVD registers are held in full; RVF values
+    /// occupy the low 32 bits).
+    fregs: [u64; 32],
+    /// The floating-point control and status register (rounding mode in
+    /// bits `[7:5]`, accrued exception flags in bits `[4:0]`).
+    fcsr: Cell<u32>,
 }
 
 impl<'a> Cpu<'a> {
-    /// Create a new CPU in a default state.
-    pub fn new(engine: &'a Engine) -> Self {
+    /// Create a new CPU in a default state, with the default TCDM,
+    /// cluster-info, scratch and timer devices mapped onto its bus.
+    pub fn new(
+        engine: &'a Engine,
+        hartid: u32,
+        tcdm: Arc<Mutex<Vec<u8>>>,
+        barrier: Arc<Barrier>,
+        mtime: Arc<AtomicU64>,
+    ) -> Self {
+        let mut bus = Bus::new();
+        bus.map(TCDM_START..TCDM_END, Box::new(TcdmDevice(tcdm)));
+        bus.map(
+            CFG_START..CFG_START + 0x18,
+            Box::new(ClusterInfoDevice {
+                tcdm_start: TCDM_START,
+                tcdm_end: TCDM_END,
+                nr_cores: engine.nr_cores as u32,
+            }),
+        );
+        bus.map(
+            SCRATCH_REG..SCRATCH_REG + 4,
+            Box::new(ScratchDevice(&engine.exit_code)),
+        );
+        let mtimecmp = Arc::new(AtomicU64::new(u64::MAX));
+        bus.map(
+            TIMER_START..TIMER_START + 0x10,
+            Box::new(TimerDevice {
+                mtime: mtime.clone(),
+                mtimecmp: mtimecmp.clone(),
+            }),
+        );
         Self {
             engine,
-            state: Default::default(),
+            state: CpuState {
+                hartid,
+                ..Default::default()
+            },
+            bus,
+            barrier,
+            mtime,
+            mtimecmp,
         }
     }
 
-    fn binary_load(&self, addr: u32, size: u8) -> u32 {
+    /// Give this core's bus a new device mapping, for users who need to
+    /// model additional peripherals (UART, performance counters, DMA/SSR
+    /// backends, ...).
+    pub fn map_device(&mut self, range: std::ops::Range<u32>, device: Box<dyn Device + 'a>) {
+        self.bus.map(range, device);
+    }
+
+    pub(crate) fn binary_load(&self, addr: u32, size: u8) -> u32 {
         trace!("Load 0x{:x} ({}B)", addr, 8 << size);
-        match addr {
-            0x40000000 => 0x42000,                     // tcdm_start
-            0x40000008 => 0x43000,                     // tcdm_end
-            0x40000010 => 1,                           // nr_cores
-            0x40000020 => self.engine.exit_code.get(), // scratch_reg
-            _ => 0,
+        let width = 1u32 << size;
+        if addr % width != 0 {
+            self.raise_trap(CAUSE_LOAD_ADDR_MISALIGNED, addr);
+            return 0;
+        }
+        match self.bus.read(addr, size) {
+            Some(value) => value,
+            None => {
+                self.raise_trap(CAUSE_LOAD_ACCESS_FAULT, addr);
+                0
+            }
         }
     }
 
-    fn binary_store(&self, addr: u32, value: u32, size: u8) {
+    pub(crate) fn binary_store(&self, addr: u32, value: u32, size: u8) {
         trace!("Store 0x{:x} = 0x{:x} ({}B)", addr, value, 8 << size);
-        match addr {
-            0x40000020 => self.engine.exit_code.set(value), // scratch_reg
-            _ => (),
+        let width = 1u32 << size;
+        if addr % width != 0 {
+            self.raise_trap(CAUSE_STORE_ADDR_MISALIGNED, addr);
+            return;
+        }
+        if !self.bus.write(addr, value, size) {
+            self.raise_trap(CAUSE_STORE_ACCESS_FAULT, addr);
         }
     }
 
     fn binary_csr_read(&self, csr: u16) -> u32 {
         trace!("Read CSR 0x{:x}", csr);
         match csr {
-            0xF14 => 0, // mhartid
+            0xF14 => self.state.hartid,              // mhartid
+            0x300 => self.state.mstatus.get(),        // mstatus
+            0x305 => self.state.mtvec.get(),           // mtvec
+            0x341 => self.state.mepc.get(),            // mepc
+            0x342 => self.state.mcause.get(),          // mcause
+            0x343 => self.state.mtval.get(),           // mtval
+            0x001 => self.state.fcsr.get() & 0x1f,     // fflags
+            0x002 => (self.state.fcsr.get() >> 5) & 0x7, // frm
+            0x003 => self.state.fcsr.get(),            // fcsr
+            0xB00 => self.state.instret as u32,        // mcycle (no stall model: cycles == instret)
+            0xB80 => (self.state.instret >> 32) as u32, // mcycleh
+            0xB02 => self.state.instret as u32,        // minstret
+            0xB82 => (self.state.instret >> 32) as u32, // minstreth
             _ => 0,
         }
     }
 
     fn binary_csr_write(&self, csr: u16, value: u32) {
         trace!("Write CSR 0x{:x} = 0x{:?}", csr, value);
+        match csr {
+            0x300 => self.state.mstatus.set(value),
+            0x305 => self.state.mtvec.set(value),
+            0x341 => self.state.mepc.set(value),
+            0x342 => self.state.mcause.set(value),
+            0x343 => self.state.mtval.set(value),
+            0x001 => self
+                .state
+                .fcsr
+                .set((self.state.fcsr.get() & !0x1f) | (value & 0x1f)),
+            0x002 => self
+                .state
+                .fcsr
+                .set((self.state.fcsr.get() & !0xe0) | ((value & 0x7) << 5)),
+            0x003 => self.state.fcsr.set(value & 0xff),
+            _ => (),
+        }
+    }
+
+    /// Record a trap: set `mcause`/`mtval`/`mepc` and mark a trap as
+    /// pending so the translated code redirects to `mtvec` on its next
+    /// check.
+    fn raise_trap(&self, cause: u32, tval: u32) {
+        trace!(
+            "Hart {} trap: cause 0x{:x}, tval 0x{:x}",
+            self.state.hartid,
+            cause,
+            tval
+        );
+        self.state.mcause.set(cause);
+        self.state.mtval.set(tval);
+        self.state.mepc.set(self.state.pc);
+        self.state.trap_pending.set(true);
+    }
+
+    /// Called by the translated code after an instruction that might have
+    /// raised a trap; returns whether a trap is pending and, if so, the
+    /// address to jump to (`mtvec`). Packed as a `u64` with bit 32 as the
+    /// "taken" flag and the low 32 bits as the jump target, rather than
+    /// overloading address `0` for "no trap" — `mtvec` legitimately resets
+    /// to `0`, so a trap raised before firmware configures a handler would
+    /// otherwise be silently dropped instead of redirecting.
+    fn binary_trap_taken(&self) -> u64 {
+        if self.state.trap_pending.get() {
+            self.state.trap_pending.set(false);
+            (1u64 << 32) | self.state.mtvec.get() as u64
+        } else {
+            0
+        }
+    }
+
+    /// Called by the translated code once per retired instruction; advances
+    /// `mtime` at the configured tick rate and raises a machine timer
+    /// interrupt through the trap path once it reaches `mtimecmp`.
+    fn binary_timer_tick(&self) {
+        let rate = self.engine.tick_rate.max(1);
+        if self.state.instret % rate != 0 {
+            return;
+        }
+        let time = self.mtime.fetch_add(1, Ordering::Relaxed) + 1;
+        if time >= self.mtimecmp.load(Ordering::Relaxed) {
+            self.raise_trap(CAUSE_MACHINE_TIMER_INTERRUPT, 0);
+        }
+    }
+
+    /// Handle an `ecall` instruction by trapping into the guest's machine
+    /// trap handler.
+    fn binary_ecall(&self) {
+        self.raise_trap(CAUSE_ECALL, 0);
+    }
+
+    /// Handle an `ebreak` instruction by trapping into the guest's machine
+    /// trap handler.
+    fn binary_ebreak(&self) {
+        self.raise_trap(CAUSE_BREAKPOINT, 0);
+    }
+
+    /// Raise an illegal-instruction trap. Used by runtime helpers (e.g. the
+    /// SSR data movers) that are handed a request which is well-formed at
+    /// the bus level but not legal given the current device state, such as
+    /// streaming through an SSR configured for the opposite direction.
+    pub(crate) fn binary_illegal_instruction(&self) {
+        self.raise_trap(CAUSE_ILLEGAL_INSTRUCTION, 0);
+    }
+
+    /// Accrue IEEE exception flags into `fcsr`.
+    fn set_fflags(&self, flags: u32) {
+        if flags != 0 {
+            self.state.fcsr.set(self.state.fcsr.get() | flags);
+        }
+    }
+
+    /// Resolve an instruction's 3-bit rounding-mode field, substituting the
+    /// dynamic rounding mode from `fcsr.frm` when `rm == 7`.
+    fn rounding_mode(&self, rm: u8) -> u8 {
+        if rm == 7 {
+            ((self.state.fcsr.get() >> 5) & 0x7) as u8
+        } else {
+            rm
+        }
+    }
+
+    /// Fused multiply-add, single precision: `a * b + c`.
+    ///
+    /// Only RNE is honored exactly: hardware `fma` already rounds the
+    /// infinite-precision product-sum once, and recovering the sign of
+    /// that rounding's error would need double-double-style extended
+    /// precision we don't have for `f32`'s widest native companion type.
+    /// Other rounding modes fall back to the native nearest-even result.
+    fn binary_fma_s(&self, a: f32, b: f32, c: f32, rm: u8) -> f32 {
+        self.rounding_mode(rm);
+        let result = a.mul_add(b, c);
+        let mut flags = 0;
+        if result.is_nan() {
+            flags |= FFLAG_NV;
+        } else if result.is_infinite() && a.is_finite() && b.is_finite() && c.is_finite() {
+            flags |= FFLAG_OF;
+        } else if result != 0.0 && result.abs() < f32::MIN_POSITIVE {
+            flags |= FFLAG_UF;
+        } else if result.is_finite() {
+            // f64 carries 29 more mantissa bits than f32, enough to tell
+            // whether a single-precision fma was exact in all but the
+            // rarest edge cases.
+            let exact = (a as f64).mul_add(b as f64, c as f64);
+            if exact != result as f64 {
+                flags |= FFLAG_NX;
+            }
+        }
+        self.set_fflags(flags);
+        result
+    }
+
+    /// Fused multiply-add, double precision: `a * b + c`. Only RNE is
+    /// honored; other rounding modes return the native nearest-even
+    /// result. The exact residual from [`fma_residual_f64`] is used only
+    /// to set the inexact flag.
+    fn binary_fma_d(&self, a: f64, b: f64, c: f64, rm: u8) -> f64 {
+        self.rounding_mode(rm);
+        let result = a.mul_add(b, c);
+        let mut flags = 0;
+        if result.is_nan() {
+            flags |= FFLAG_NV;
+        } else if result.is_infinite() && a.is_finite() && b.is_finite() && c.is_finite() {
+            flags |= FFLAG_OF;
+        } else if result != 0.0 && result.abs() < f64::MIN_POSITIVE {
+            flags |= FFLAG_UF;
+        } else if result.is_finite() && fma_residual_f64(a, b, c, result) != 0.0 {
+            flags |= FFLAG_NX;
+        }
+        self.set_fflags(flags);
+        result
+    }
+
+    /// Floating-point division, single precision. Honors `rm` by nudging
+    /// the native nearest-even result by at most one ULP, using the exact
+    /// (fma-computed) residual of `a - result * b` to find which way the
+    /// true quotient lies.
+    fn binary_fdiv_s(&self, a: f32, b: f32, rm: u8) -> f32 {
+        let rm = self.rounding_mode(rm);
+        let mut result = a / b;
+        let mut flags = 0;
+        if a.is_nan() || b.is_nan() || (a == 0.0 && b == 0.0) || (a.is_infinite() && b.is_infinite())
+        {
+            flags |= FFLAG_NV;
+        } else if b == 0.0 {
+            flags |= FFLAG_DZ;
+        } else if result.is_infinite() && a.is_finite() && b.is_finite() {
+            flags |= FFLAG_OF;
+        } else if result != 0.0 && result.abs() < f32::MIN_POSITIVE {
+            flags |= FFLAG_UF;
+        } else {
+            let residual = (-result).mul_add(b, a);
+            if residual != 0.0 {
+                flags |= FFLAG_NX;
+            }
+            let error = if b > 0.0 { residual } else { -residual };
+            result = round_directed_f32(result, error, rm);
+        }
+        self.set_fflags(flags);
+        result
+    }
+
+    /// Floating-point division, double precision. See
+    /// [`Self::binary_fdiv_s`] for the rounding approach.
+    fn binary_fdiv_d(&self, a: f64, b: f64, rm: u8) -> f64 {
+        let rm = self.rounding_mode(rm);
+        let mut result = a / b;
+        let mut flags = 0;
+        if a.is_nan() || b.is_nan() || (a == 0.0 && b == 0.0) || (a.is_infinite() && b.is_infinite())
+        {
+            flags |= FFLAG_NV;
+        } else if b == 0.0 {
+            flags |= FFLAG_DZ;
+        } else if result.is_infinite() && a.is_finite() && b.is_finite() {
+            flags |= FFLAG_OF;
+        } else if result != 0.0 && result.abs() < f64::MIN_POSITIVE {
+            flags |= FFLAG_UF;
+        } else {
+            let residual = (-result).mul_add(b, a);
+            if residual != 0.0 {
+                flags |= FFLAG_NX;
+            }
+            let error = if b > 0.0 { residual } else { -residual };
+            result = round_directed_f64(result, error, rm);
+        }
+        self.set_fflags(flags);
+        result
+    }
+
+    /// Floating-point square root, single precision. Honors `rm` the same
+    /// way as [`Self::binary_fdiv_s`], using the residual of
+    /// `a - result * result`.
+    fn binary_fsqrt_s(&self, a: f32, rm: u8) -> f32 {
+        let rm = self.rounding_mode(rm);
+        if a.is_nan() || a < 0.0 {
+            self.set_fflags(FFLAG_NV);
+            return a.sqrt();
+        }
+        let result = a.sqrt();
+        let residual = (-result).mul_add(result, a);
+        if residual != 0.0 {
+            self.set_fflags(FFLAG_NX);
+        }
+        round_directed_f32(result, residual, rm)
+    }
+
+    /// Floating-point square root, double precision. See
+    /// [`Self::binary_fsqrt_s`] for the rounding approach.
+    fn binary_fsqrt_d(&self, a: f64, rm: u8) -> f64 {
+        let rm = self.rounding_mode(rm);
+        if a.is_nan() || a < 0.0 {
+            self.set_fflags(FFLAG_NV);
+            return a.sqrt();
+        }
+        let result = a.sqrt();
+        let residual = (-result).mul_add(result, a);
+        if residual != 0.0 {
+            self.set_fflags(FFLAG_NX);
+        }
+        round_directed_f64(result, residual, rm)
+    }
+
+    /// Convert a single-precision float to a signed 32-bit integer.
+    fn binary_fcvt_w_s(&self, a: f32, rm: u8) -> i32 {
+        let rm = self.rounding_mode(rm);
+        if a.is_nan() || a >= 2147483648.0 {
+            self.set_fflags(FFLAG_NV);
+            return i32::MAX;
+        }
+        if a < -2147483648.0 {
+            self.set_fflags(FFLAG_NV);
+            return i32::MIN;
+        }
+        let rounded = round_for_mode_f32(a, rm);
+        if rounded != a {
+            self.set_fflags(FFLAG_NX);
+        }
+        rounded as i32
+    }
+
+    /// Convert a single-precision float to an unsigned 32-bit integer.
+    fn binary_fcvt_wu_s(&self, a: f32, rm: u8) -> u32 {
+        let rm = self.rounding_mode(rm);
+        if a.is_nan() || a >= 4294967296.0 || a < 0.0 {
+            self.set_fflags(FFLAG_NV);
+            return if a.is_nan() || a >= 4294967296.0 {
+                u32::MAX
+            } else {
+                0
+            };
+        }
+        let rounded = round_for_mode_f32(a, rm);
+        if rounded != a {
+            self.set_fflags(FFLAG_NX);
+        }
+        rounded as u32
+    }
+
+    /// Convert a signed 32-bit integer to a single-precision float. `a`
+    /// always fits exactly in an `f64`, so we widen there, round the exact
+    /// value to `f32` to nearest-even (the native cast), then — like
+    /// [`Self::binary_fdiv_s`] — nudge by at most one ULP using the exact
+    /// residual to honor directed rounding modes.
+    fn binary_fcvt_s_w(&self, a: i32, rm: u8) -> f32 {
+        let rm = self.rounding_mode(rm);
+        let nearest = a as f32;
+        let error = (a as f64 - nearest as f64) as f32;
+        let result = round_directed_f32(nearest, error, rm);
+        if result as f64 != a as f64 {
+            self.set_fflags(FFLAG_NX);
+        }
+        result
+    }
+
+    /// Convert an unsigned 32-bit integer to a single-precision float. See
+    /// [`Self::binary_fcvt_s_w`] for the rounding approach.
+    fn binary_fcvt_s_wu(&self, a: u32, rm: u8) -> f32 {
+        let rm = self.rounding_mode(rm);
+        let nearest = a as f32;
+        let error = (a as f64 - nearest as f64) as f32;
+        let result = round_directed_f32(nearest, error, rm);
+        if result as f64 != a as f64 {
+            self.set_fflags(FFLAG_NX);
+        }
+        result
+    }
+
+    /// Convert a double-precision float to a signed 32-bit integer.
+    fn binary_fcvt_w_d(&self, a: f64, rm: u8) -> i32 {
+        let rm = self.rounding_mode(rm);
+        if a.is_nan() || a >= 2147483648.0 {
+            self.set_fflags(FFLAG_NV);
+            return i32::MAX;
+        }
+        if a < -2147483648.0 {
+            self.set_fflags(FFLAG_NV);
+            return i32::MIN;
+        }
+        let rounded = round_for_mode_f64(a, rm);
+        if rounded != a {
+            self.set_fflags(FFLAG_NX);
+        }
+        rounded as i32
+    }
+
+    /// Convert a double-precision float to an unsigned 32-bit integer.
+    fn binary_fcvt_wu_d(&self, a: f64, rm: u8) -> u32 {
+        let rm = self.rounding_mode(rm);
+        if a.is_nan() || a >= 4294967296.0 || a < 0.0 {
+            self.set_fflags(FFLAG_NV);
+            return if a.is_nan() || a >= 4294967296.0 {
+                u32::MAX
+            } else {
+                0
+            };
+        }
+        let rounded = round_for_mode_f64(a, rm);
+        if rounded != a {
+            self.set_fflags(FFLAG_NX);
+        }
+        rounded as u32
+    }
+
+    /// Convert a signed 32-bit integer to a double-precision float.
+    fn binary_fcvt_d_w(&self, a: i32) -> f64 {
+        a as f64
+    }
+
+    /// Convert an unsigned 32-bit integer to a double-precision float.
+    fn binary_fcvt_d_wu(&self, a: u32) -> f64 {
+        a as f64
+    }
+
+    /// Block this hart until every other hart in the cluster has also
+    /// called the barrier.
+    fn binary_barrier(&self) {
+        trace!("Hart {} waiting at barrier", self.state.hartid);
+        self.barrier.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_for_mode_f32_picks_the_right_direction() {
+        // 2.5 is exactly representable and sits on a tie, so RNE and RMM
+        // part ways while the directed modes follow their direction.
+        assert_eq!(round_for_mode_f32(2.5, 0), 2.0); // RNE: ties to even
+        assert_eq!(round_for_mode_f32(2.5, 1), 2.0); // RTZ: truncate
+        assert_eq!(round_for_mode_f32(2.5, 2), 2.0); // RDN: floor
+        assert_eq!(round_for_mode_f32(2.5, 3), 3.0); // RUP: ceil
+        assert_eq!(round_for_mode_f32(2.5, 4), 3.0); // RMM: ties away from 0
+        assert_eq!(round_for_mode_f32(-2.5, 1), -2.0);
+        assert_eq!(round_for_mode_f32(-2.5, 2), -3.0);
+        assert_eq!(round_for_mode_f32(-2.5, 3), -2.0);
+    }
+
+    #[test]
+    fn round_for_mode_f64_picks_the_right_direction() {
+        assert_eq!(round_for_mode_f64(2.5, 1), 2.0);
+        assert_eq!(round_for_mode_f64(2.5, 2), 2.0);
+        assert_eq!(round_for_mode_f64(2.5, 3), 3.0);
+        assert_eq!(round_for_mode_f64(-2.5, 2), -3.0);
+    }
+
+    #[test]
+    fn next_up_down_f32_step_by_one_ulp() {
+        let x = 1.0f32;
+        assert!(next_up_f32(x) > x);
+        assert!(next_down_f32(x) < x);
+        assert_eq!(next_down_f32(next_up_f32(x)), x);
+        // Stepping up from zero lands on the smallest positive subnormal.
+        assert_eq!(next_up_f32(0.0), f32::from_bits(1));
+        assert_eq!(next_down_f32(0.0), -next_up_f32(0.0));
+    }
+
+    #[test]
+    fn round_directed_f32_nudges_by_at_most_one_ulp() {
+        let nearest = 1.0f32;
+        // error > 0 means the exact value was above `nearest`.
+        assert_eq!(round_directed_f32(nearest, 1.0, 3 /* RUP */), next_up_f32(nearest));
+        assert_eq!(round_directed_f32(nearest, 1.0, 2 /* RDN */), nearest);
+        // error < 0 means the exact value was below `nearest`.
+        assert_eq!(round_directed_f32(nearest, -1.0, 1 /* RTZ */), next_down_f32(nearest));
+        // RNE/RMM trust the input unchanged.
+        assert_eq!(round_directed_f32(nearest, 1.0, 0), nearest);
+        assert_eq!(round_directed_f32(nearest, 1.0, 4), nearest);
+        // No error means nothing to nudge.
+        assert_eq!(round_directed_f32(nearest, 0.0, 1), nearest);
+    }
+
+    #[test]
+    fn round_directed_f64_nudges_by_at_most_one_ulp() {
+        let nearest = 1.0f64;
+        assert_eq!(round_directed_f64(nearest, 1.0, 3 /* RUP */), next_up_f64(nearest));
+        assert_eq!(round_directed_f64(nearest, -1.0, 2 /* RDN */), next_down_f64(nearest));
+        assert_eq!(round_directed_f64(nearest, 1.0, 0), nearest);
+    }
+
+    #[test]
+    fn fma_residual_f64_is_zero_for_exact_fma() {
+        // 1.0 * 2.0 + 3.0 is exactly representable, so the fma is exact.
+        let result = 1.0f64.mul_add(2.0, 3.0);
+        assert_eq!(fma_residual_f64(1.0, 2.0, 3.0, result), 0.0);
+    }
+
+    #[test]
+    fn fma_residual_f64_recovers_the_rounding_error() {
+        // Chosen so that a * b + c is not exactly representable in f64.
+        let (a, b, c) = (1.0 + f64::EPSILON, 1.0 + f64::EPSILON, -2.0);
+        let result = a.mul_add(b, c);
+        let residual = fma_residual_f64(a, b, c, result);
+        // The exact value is -1 + 2 * EPSILON + EPSILON^2; the fma rounds
+        // away the EPSILON^2 term, which is what the residual recovers.
+        assert_eq!(residual, f64::EPSILON * f64::EPSILON);
+        // The residual plus the rounded result should land back on the
+        // true value to within the precision a single f64 addition has.
+        assert_eq!(result + residual, (a as f64) * (b as f64) + (c as f64));
     }
 }
@@ -1,5 +1,7 @@
 //! Runtime cod included as LLVM IR in the translated binary.
 
+use crate::engine::Cpu;
+
 /// A representation of a single SSR address generator's state.
 #[derive(Default)]
 #[repr(C)]
@@ -48,7 +50,7 @@ pub unsafe fn banshee_ssr_write_cfg(ssr: &mut SsrState, addr: u32, value: u32) {
             ssr.write = ((value >> 30) & 1) != 0;
             ssr.dims = ((value >> 28) & 3) as u8;
         }
-        1 => ssr.repeat_count = value as u16,
+        1 => ssr.repeat_bound = value as u16,
         2..=5 => *ssr.bound.get_unchecked_mut(addr - 2) = value,
         6..=9 => *ssr.stride.get_unchecked_mut(addr - 6) = value,
         24..=27 => {
@@ -74,7 +76,7 @@ pub unsafe fn banshee_ssr_read_cfg(ssr: &mut SsrState, addr: u32) -> u32 {
     let addr = addr as usize / 8;
     match addr {
         0 => ssr.ptr | (ssr.done as u32) << 31 | (ssr.write as u32) << 30 | (ssr.dims as u32) << 28,
-        1 => ssr.repeat_count as u32,
+        1 => ssr.repeat_bound as u32,
         2..=5 => *ssr.bound.get_unchecked(addr - 2),
         6..=9 => *ssr.stride.get_unchecked(addr - 6),
         // TODO: Issue an error
@@ -108,6 +110,29 @@ pub unsafe fn banshee_ssr_next(ssr: &mut SsrState) -> u32 {
     ptr
 }
 
+/// Stream a word in from the address generated by an SSR, turning the
+/// address-only model into an actual data mover.
+#[no_mangle]
+pub unsafe fn banshee_ssr_read(cpu: &Cpu, ssr: &mut SsrState) -> u32 {
+    if ssr.write {
+        cpu.binary_illegal_instruction();
+        return 0;
+    }
+    let addr = banshee_ssr_next(ssr);
+    cpu.binary_load(addr, 2)
+}
+
+/// Stream a word out to the address generated by an SSR.
+#[no_mangle]
+pub unsafe fn banshee_ssr_write(cpu: &Cpu, ssr: &mut SsrState, value: u32) {
+    if !ssr.write {
+        cpu.binary_illegal_instruction();
+        return;
+    }
+    let addr = banshee_ssr_next(ssr);
+    cpu.binary_store(addr, value, 2);
+}
+
 /// A representation of a DMA backend's state.
 #[derive(Default)]
 #[repr(C)]
@@ -132,6 +157,43 @@ pub unsafe fn banshee_dma_src(dma: &mut DmaState, lo: u32, hi: u32) {
     dma.src = (hi as u64) << 32 | (lo as u64);
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeat_bound_repeats_each_address_bound_plus_one_times() {
+        let mut ssr = SsrState {
+            bound: [u32::MAX, 0, 0, 0],
+            stride: [4, 0, 0, 0],
+            ptr: 0x100,
+            repeat_bound: 2,
+            ..Default::default()
+        };
+        let addrs: Vec<_> = (0..7).map(|_| unsafe { banshee_ssr_next(&mut ssr) }).collect();
+        assert_eq!(
+            addrs,
+            vec![0x100, 0x100, 0x100, 0x104, 0x104, 0x104, 0x108]
+        );
+    }
+
+    #[test]
+    fn multi_dim_address_generation_carries_between_dims() {
+        // A 2-dim (dims=1) generator: the inner dim (index 0) has 2 steps
+        // of stride 4 before carrying into the outer dim (index 1), which
+        // strides by 100.
+        let mut ssr = SsrState {
+            dims: 1,
+            bound: [1, 1, 0, 0],
+            stride: [4, 100, 0, 0],
+            ptr: 0,
+            ..Default::default()
+        };
+        let addrs: Vec<_> = (0..6).map(|_| unsafe { banshee_ssr_next(&mut ssr) }).collect();
+        assert_eq!(addrs, vec![0, 4, 104, 108, 208, 212]);
+    }
+}
+
 /// Implementation of the `dm.dst` instruction.
 #[no_mangle]
 pub unsafe fn banshee_dma_dst(dma: &mut DmaState, lo: u32, hi: u32) {